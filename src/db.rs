@@ -1,69 +1,500 @@
 use rusqlite::{params, Connection, Result};
-use crate::model::InventoryBatch;
+use crate::model::{ChangeEvent, Dimension, InventoryBatch, Measure, SpecDef, SpecError};
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use std::path::Path;
 
 #[derive(Clone)]
 pub struct Database {
     conn: Arc<Mutex<Connection>>,
+    /// Handlers notified with each `ChangeEvent`, after the transaction that
+    /// produced it commits. `Arc<Mutex<..>>`, not `Rc<RefCell<..>>`, so
+    /// `Database` itself stays `Send + Sync` like `conn` already is — the
+    /// integration point this exists for (a background export, an async
+    /// logger) needs to be able to register/receive off the UI thread.
+    observers: Arc<Mutex<Vec<Box<dyn Fn(&ChangeEvent) + Send>>>>,
+}
+
+/// Wraps a non-empty `Vec<SpecError>` so it can travel through a
+/// `rusqlite::Error::ToSqlConversionFailure`, the same pattern this file
+/// already uses to surface serde_json errors.
+#[derive(Debug)]
+struct SpecValidationError(Vec<SpecError>);
+
+impl std::fmt::Display for SpecValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let messages: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        write!(f, "spec validation failed: {}", messages.join("; "))
+    }
+}
+
+impl std::error::Error for SpecValidationError {}
+
+/// A batch's `weight`/`price` was NaN or infinite. `f64::parse` happily
+/// accepts the literal strings "NaN"/"inf", but `serde_json` writes
+/// non-finite floats as `null`, which `batch_from_attrs` can't read back as
+/// an `f64`, so this is caught here before `record_batch_datoms` runs.
+#[derive(Debug)]
+struct NonFiniteError(&'static str, f64);
+
+impl std::fmt::Display for NonFiniteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} must be a finite number, got {}", self.0, self.1)
+    }
+}
+
+impl std::error::Error for NonFiniteError {}
+
+/// Running totals for one group of `Database::report_groups`. `spec_sums`
+/// tracks a separate weighted sum/weight pair per distinct `SpecAvg` key
+/// requested, since not every batch in a group carries every spec.
+#[derive(Default)]
+struct GroupAccum {
+    count: i64,
+    weight: f64,
+    value: f64,
+    spec_sums: HashMap<String, (f64, f64)>,
+}
+
+/// A single schema change, applied once when the database's `PRAGMA
+/// user_version` is below the migration's version (its 1-based position in
+/// `MIGRATIONS`). Migrations only ever move
+/// forward: add tables/columns/indexes, backfill data. Never edit a migration
+/// that has shipped — append a new one instead.
+type Migration = fn(&Connection) -> Result<()>;
+
+/// The schema version this binary expects. Bump by exactly one and append a
+/// migration to `MIGRATIONS` whenever the schema changes.
+const CURRENT_VERSION: i32 = 4;
+
+const MIGRATIONS: &[Migration] = &[migration_v1, migration_v2, migration_v3, migration_v4];
+
+/// v1: the original four tables plus the append-only transaction/datom log.
+fn migration_v1(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS inventory_batches (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            type_id INTEGER,
+            grade_id INTEGER,
+            stage_id INTEGER,
+            weight REAL NOT NULL,
+            price REAL NOT NULL,
+            specs_json TEXT NOT NULL,
+            created_at TEXT DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE TABLE IF NOT EXISTS stages (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE IF NOT EXISTS grades (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE
+        );
+
+        CREATE TABLE IF NOT EXISTS product_types (
+            id INTEGER PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            specs_keys_json TEXT NOT NULL
+        );
+
+        CREATE TABLE IF NOT EXISTS transactions (
+            tx_id INTEGER PRIMARY KEY,
+            wall_time TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );
+
+        CREATE TABLE IF NOT EXISTS datoms (
+            tx_id INTEGER NOT NULL REFERENCES transactions(tx_id),
+            batch_id INTEGER NOT NULL,
+            attribute TEXT NOT NULL,
+            value_json TEXT NOT NULL,
+            added BOOLEAN NOT NULL
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_datoms_batch ON datoms (batch_id, tx_id);",
+    )
+}
+
+/// v2: stage-transition history, recording the weight lost/gained (and the
+/// resulting yield ratio) each time a batch moves between workflow stages.
+fn migration_v2(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS stage_transitions (
+            id INTEGER PRIMARY KEY,
+            batch_id INTEGER NOT NULL REFERENCES inventory_batches(id),
+            from_stage_id INTEGER NOT NULL,
+            to_stage_id INTEGER NOT NULL,
+            weight_before REAL NOT NULL,
+            weight_after REAL NOT NULL,
+            yield_ratio REAL NOT NULL,
+            wall_time TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+            note TEXT
+        );
+
+        CREATE INDEX IF NOT EXISTS idx_stage_transitions_batch ON stage_transitions (batch_id);",
+    )
+}
+
+/// v3: a materialized per-stage total, maintained incrementally instead of
+/// re-scanning `inventory_batches` on every dashboard read. Backfilled from
+/// whatever rows already exist.
+fn migration_v3(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS stage_aggregates (
+            stage_id INTEGER PRIMARY KEY,
+            total_weight REAL NOT NULL DEFAULT 0.0,
+            batch_count INTEGER NOT NULL DEFAULT 0
+        );
+
+        INSERT OR IGNORE INTO stage_aggregates (stage_id, total_weight, batch_count)
+        SELECT stage_id, COALESCE(SUM(weight), 0.0), COUNT(*) FROM inventory_batches GROUP BY stage_id;",
+    )
+}
+
+/// v4: per-type spec type descriptors (unit, bounds, required), so a batch's
+/// free-form `specs` map can be checked before it's written.
+fn migration_v4(conn: &Connection) -> Result<()> {
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS spec_defs (
+            type_id INTEGER NOT NULL REFERENCES product_types(id),
+            key TEXT NOT NULL,
+            unit TEXT NOT NULL DEFAULT '',
+            min_value REAL,
+            max_value REAL,
+            required BOOLEAN NOT NULL DEFAULT 1,
+            PRIMARY KEY (type_id, key)
+        );",
+    )?;
+
+    // Backfill spec_defs for product types that already existed before this
+    // migration ran, the same way insert_product_type seeds them for new
+    // ones (required, no bounds) — otherwise every previously-created type
+    // has zero SpecDefs and check_specs rejects all of its batches' specs
+    // as Unknown.
+    let mut stmt = conn.prepare("SELECT id, specs_keys_json FROM product_types")?;
+    let rows = stmt.query_map([], |row| {
+        let id: i64 = row.get(0)?;
+        let specs_keys_json: String = row.get(1)?;
+        Ok((id, specs_keys_json))
+    })?;
+    for row in rows {
+        let (type_id, specs_keys_json) = row?;
+        let keys: Vec<String> = serde_json::from_str(&specs_keys_json)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        for key in &keys {
+            conn.execute(
+                "INSERT OR IGNORE INTO spec_defs (type_id, key, unit, min_value, max_value, required)
+                 VALUES (?1, ?2, '', NULL, NULL, 1)",
+                params![type_id, key],
+            )?;
+        }
+    }
+    Ok(())
 }
 
 impl Database {
     pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let conn = Connection::open(path)?;
+        let mut conn = Connection::open(path)?;
 
         conn.execute("PRAGMA foreign_keys = ON;", [])?;
         let _mode: String = conn.query_row("PRAGMA journal_mode = WAL;", [], |row| row.get(0))?;
 
-        // 1. Inventory Batches
+        Self::run_migrations(&mut conn)?;
+
+        Ok(Database {
+            conn: Arc::new(Mutex::new(conn)),
+            observers: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// Registers a handler invoked with every `ChangeEvent` a mutating
+    /// method emits, after its transaction has committed. Registering
+    /// replaces the scattered manual UI-refresh calls that used to follow
+    /// each callback, and is the integration point for future exports or
+    /// logging.
+    pub fn on_change<F: Fn(&ChangeEvent) + Send + 'static>(&self, handler: F) {
+        self.observers.lock().unwrap().push(Box::new(handler));
+    }
+
+    /// Notifies every registered observer. Only called after a transaction
+    /// has committed successfully, so handlers never see a partial write.
+    fn emit(&self, event: ChangeEvent) {
+        for handler in self.observers.lock().unwrap().iter() {
+            handler(&event);
+        }
+    }
+
+    /// Brings the schema up to `CURRENT_VERSION`, applying each pending
+    /// migration inside its own transaction and bumping `user_version` only
+    /// after it commits successfully. A `user_version` ahead of
+    /// `CURRENT_VERSION` means this binary is older than the database and
+    /// refuses to run, rather than risk misreading a newer schema.
+    fn run_migrations(conn: &mut Connection) -> Result<()> {
+        let current: i32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if current > CURRENT_VERSION {
+            let msg = format!(
+                "database schema version {} is newer than this binary supports ({}); refusing to open",
+                current, CURRENT_VERSION
+            );
+            return Err(rusqlite::Error::ToSqlConversionFailure(Box::new(
+                std::io::Error::new(std::io::ErrorKind::Other, msg),
+            )));
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            let version = (index + 1) as i32;
+            if version <= current {
+                continue;
+            }
+            let tx = conn.transaction()?;
+            migration(&tx)?;
+            tx.pragma_update(None, "user_version", version)?;
+            tx.commit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Opens a new transaction row stamped with the current wall-clock time and
+    /// returns its `tx_id`. Callers follow up with one or more `record_datom`
+    /// calls against the same `conn` inside the same SQL transaction.
+    fn begin_tx(conn: &Connection) -> Result<i64> {
+        conn.execute("INSERT INTO transactions (wall_time) VALUES (CURRENT_TIMESTAMP)", [])?;
+        Ok(conn.last_insert_rowid())
+    }
+
+    /// Appends a single fact to the datom log. `added = false` records a
+    /// retraction; datoms are never updated or deleted.
+    fn record_datom(conn: &Connection, tx_id: i64, batch_id: i64, attribute: &str, value_json: &str, added: bool) -> Result<()> {
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS inventory_batches (
-                id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL,
-                type_id INTEGER,
-                grade_id INTEGER,
-                stage_id INTEGER,
-                weight REAL NOT NULL,
-                price REAL NOT NULL,
-                specs_json TEXT NOT NULL,
-                created_at TEXT DEFAULT CURRENT_TIMESTAMP
-            )",
-            [],
+            "INSERT INTO datoms (tx_id, batch_id, attribute, value_json, added) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![tx_id, batch_id, attribute, value_json, added],
         )?;
+        Ok(())
+    }
 
-        // 2. Stages
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS stages (
-                id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE
-            )",
-            [],
+    /// Writes one datom per attribute of `b` for `batch_id` under `tx_id`,
+    /// marking each as added.
+    fn record_batch_datoms(conn: &Connection, tx_id: i64, batch_id: i64, b: &InventoryBatch) -> Result<()> {
+        fn to_json<T: serde::Serialize>(v: &T) -> Result<String> {
+            serde_json::to_string(v).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+        }
+        Self::record_datom(conn, tx_id, batch_id, "name", &to_json(&b.name)?, true)?;
+        Self::record_datom(conn, tx_id, batch_id, "type_id", &to_json(&b.type_id)?, true)?;
+        Self::record_datom(conn, tx_id, batch_id, "grade_id", &to_json(&b.grade_id)?, true)?;
+        Self::record_datom(conn, tx_id, batch_id, "stage_id", &to_json(&b.stage_id)?, true)?;
+        Self::record_datom(conn, tx_id, batch_id, "weight", &to_json(&b.weight)?, true)?;
+        Self::record_datom(conn, tx_id, batch_id, "price", &to_json(&b.price)?, true)?;
+        Self::record_datom(conn, tx_id, batch_id, "specs", &to_json(&b.specs)?, true)?;
+        Ok(())
+    }
+
+    /// Folds a single batch's datoms into its current attribute map, without
+    /// reconstructing the rest of the inventory.
+    fn current_attrs(conn: &Connection, batch_id: i64) -> Result<HashMap<String, String>> {
+        let mut stmt = conn.prepare(
+            "SELECT attribute, value_json, added FROM datoms WHERE batch_id = ?1 ORDER BY tx_id ASC",
         )?;
+        let rows = stmt.query_map(params![batch_id], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, bool>(2)?))
+        })?;
+        let mut attrs = HashMap::new();
+        for r in rows {
+            let (attribute, value_json, added) = r?;
+            if added {
+                attrs.insert(attribute, value_json);
+            } else {
+                attrs.remove(&attribute);
+            }
+        }
+        Ok(attrs)
+    }
 
-        // 3. Grades
+    /// Adds `delta_weight`/`delta_count` to `stage_id`'s cached totals,
+    /// creating the row if it doesn't exist yet. Always called in the same
+    /// SQL transaction as the `inventory_batches` write it reflects, so the
+    /// cache can never drift from the source table.
+    fn adjust_stage_aggregate(conn: &Connection, stage_id: i64, delta_weight: f64, delta_count: i64) -> Result<()> {
         conn.execute(
-            "CREATE TABLE IF NOT EXISTS grades (
-                id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE
-            )",
-            [],
+            "INSERT INTO stage_aggregates (stage_id, total_weight, batch_count) VALUES (?1, ?2, ?3)
+             ON CONFLICT(stage_id) DO UPDATE SET
+                total_weight = total_weight + excluded.total_weight,
+                batch_count = batch_count + excluded.batch_count",
+            params![stage_id, delta_weight, delta_count],
         )?;
+        Ok(())
+    }
 
-        // 4. Product Types
-        conn.execute(
-            "CREATE TABLE IF NOT EXISTS product_types (
-                id INTEGER PRIMARY KEY,
-                name TEXT NOT NULL UNIQUE,
-                specs_keys_json TEXT NOT NULL
-            )",
-            [],
+    /// Reads the `SpecDef`s registered for a product type.
+    fn fetch_spec_defs(conn: &Connection, type_id: i64) -> Result<Vec<SpecDef>> {
+        let mut stmt = conn.prepare(
+            "SELECT key, unit, min_value, max_value, required FROM spec_defs WHERE type_id = ?1",
         )?;
+        let rows = stmt.query_map(params![type_id], |row| {
+            Ok(SpecDef {
+                key: row.get(0)?,
+                unit: row.get(1)?,
+                min: row.get(2)?,
+                max: row.get(3)?,
+                required: row.get(4)?,
+            })
+        })?;
+        let mut result = Vec::new();
+        for r in rows { result.push(r?); }
+        Ok(result)
+    }
 
-        Ok(Database {
-            conn: Arc::new(Mutex::new(conn)),
+    /// Rejects NaN/infinite values before they reach `serde_json`, which
+    /// would silently encode them as `null` in the datom log.
+    fn check_finite(name: &'static str, value: f64) -> Result<()> {
+        if value.is_finite() {
+            Ok(())
+        } else {
+            Err(rusqlite::Error::ToSqlConversionFailure(Box::new(NonFiniteError(name, value))))
+        }
+    }
+
+    /// Checks `b.specs` against `defs`: every required key must be present,
+    /// every supplied value must fall within its bounds, and every supplied
+    /// key must be known to the product type.
+    fn check_specs(defs: &[SpecDef], b: &InventoryBatch) -> Vec<SpecError> {
+        let mut errors = Vec::new();
+        let known: std::collections::HashSet<&str> = defs.iter().map(|d| d.key.as_str()).collect();
+
+        for def in defs {
+            match b.specs.get(&def.key) {
+                Some(&value) => {
+                    let below_min = def.min.map_or(false, |min| value < min);
+                    let above_max = def.max.map_or(false, |max| value > max);
+                    if below_min || above_max {
+                        errors.push(SpecError::OutOfBounds {
+                            key: def.key.clone(),
+                            value,
+                            min: def.min,
+                            max: def.max,
+                        });
+                    }
+                }
+                None if def.required => errors.push(SpecError::Missing(def.key.clone())),
+                None => {}
+            }
+        }
+
+        for key in b.specs.keys() {
+            if !known.contains(key.as_str()) {
+                errors.push(SpecError::Unknown(key.clone()));
+            }
+        }
+
+        errors
+    }
+
+    /// Folds every datom with `tx_id <= as_of_tx`, in ascending `tx_id` order,
+    /// into a per-batch attribute map: `added=true` sets the attribute,
+    /// `added=false` removes it.
+    fn fold_datoms(conn: &Connection, as_of_tx: i64) -> Result<HashMap<i64, HashMap<String, String>>> {
+        let mut stmt = conn.prepare(
+            "SELECT batch_id, attribute, value_json, added FROM datoms WHERE tx_id <= ?1 ORDER BY tx_id ASC",
+        )?;
+        let rows = stmt.query_map(params![as_of_tx], |row| {
+            Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get::<_, String>(2)?, row.get::<_, bool>(3)?))
+        })?;
+
+        let mut state: HashMap<i64, HashMap<String, String>> = HashMap::new();
+        for r in rows {
+            let (batch_id, attribute, value_json, added) = r?;
+            let attrs = state.entry(batch_id).or_default();
+            if added {
+                attrs.insert(attribute, value_json);
+            } else {
+                attrs.remove(&attribute);
+            }
+        }
+        Ok(state)
+    }
+
+    /// Reconstructs an `InventoryBatch` from its folded attribute map. Returns
+    /// `None` if any required attribute is missing (e.g. the batch was fully
+    /// retracted).
+    fn batch_from_attrs(attrs: &HashMap<String, String>) -> Option<InventoryBatch> {
+        Some(InventoryBatch {
+            name: serde_json::from_str(attrs.get("name")?).ok()?,
+            type_id: serde_json::from_str(attrs.get("type_id")?).ok()?,
+            grade_id: serde_json::from_str(attrs.get("grade_id")?).ok()?,
+            stage_id: serde_json::from_str(attrs.get("stage_id")?).ok()?,
+            weight: serde_json::from_str(attrs.get("weight")?).ok()?,
+            price: serde_json::from_str(attrs.get("price")?).ok()?,
+            specs: serde_json::from_str(attrs.get("specs")?).ok()?,
         })
     }
 
+    /// Returns the highest `tx_id` whose `wall_time <= timestamp`, or 0 (no
+    /// transactions) if none qualify.
+    fn tx_as_of(conn: &Connection, timestamp: &str) -> Result<i64> {
+        conn.query_row(
+            "SELECT COALESCE(MAX(tx_id), 0) FROM transactions WHERE wall_time <= ?1",
+            params![timestamp],
+            |row| row.get(0),
+        )
+    }
+
+    /// Reconstructs every inventory batch as it existed at `timestamp`
+    /// (an ISO-8601 / `CURRENT_TIMESTAMP`-formatted string), by folding the
+    /// datom log up to the transaction current at that time.
+    pub fn inventory_as_of(&self, timestamp: &str) -> Result<Vec<(i64, InventoryBatch)>> {
+        let conn = self.conn.lock().unwrap();
+        let as_of_tx = Self::tx_as_of(&conn, timestamp)?;
+        let state = Self::fold_datoms(&conn, as_of_tx)?;
+
+        let mut result: Vec<(i64, InventoryBatch)> = state
+            .into_iter()
+            .filter_map(|(batch_id, attrs)| Self::batch_from_attrs(&attrs).map(|b| (batch_id, b)))
+            .collect();
+        result.sort_by_key(|(id, _)| *id);
+        Ok(result)
+    }
+
+    /// Returns the full change history of a batch as
+    /// `(tx_id, wall_time, attribute, old_value, new_value)` tuples in
+    /// ascending `tx_id` order. `old_value` is `None` for the first time an
+    /// attribute is set.
+    pub fn batch_history(&self, batch_id: i64) -> Result<Vec<(i64, String, String, Option<String>, Option<String>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT d.tx_id, t.wall_time, d.attribute, d.value_json, d.added
+             FROM datoms d JOIN transactions t ON t.tx_id = d.tx_id
+             WHERE d.batch_id = ?1 ORDER BY d.tx_id ASC",
+        )?;
+        let rows = stmt.query_map(params![batch_id], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, bool>(4)?,
+            ))
+        })?;
+
+        let mut history = Vec::new();
+        let mut current: HashMap<String, String> = HashMap::new();
+        for r in rows {
+            let (tx_id, wall_time, attribute, value_json, added) = r?;
+            let old = current.get(&attribute).cloned();
+            let new = if added {
+                current.insert(attribute.clone(), value_json.clone());
+                Some(value_json)
+            } else {
+                current.remove(&attribute);
+                None
+            };
+            history.push((tx_id, wall_time, attribute, old, new));
+        }
+        Ok(history)
+    }
+
     /// Pre-populates the DB with the user's requested defaults if empty
     pub fn seed_defaults(&self) -> Result<()> {
         let conn = self.conn.lock().unwrap();
@@ -93,31 +524,277 @@ impl Database {
 
     // --- Inventory Methods ---
     pub fn insert_inventory_batch(&self, b: &InventoryBatch) -> Result<i64> {
+        let batch_id = {
+            let mut conn = self.conn.lock().unwrap();
+            let specs_str = serde_json::to_string(&b.specs)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+            Self::check_finite("weight", b.weight)?;
+            Self::check_finite("price", b.price)?;
+
+            let tx = conn.transaction()?;
+
+            let defs = Self::fetch_spec_defs(&tx, b.type_id)?;
+            let errors = Self::check_specs(&defs, b);
+            if !errors.is_empty() {
+                return Err(rusqlite::Error::ToSqlConversionFailure(Box::new(SpecValidationError(errors))));
+            }
+
+            tx.execute(
+                "INSERT INTO inventory_batches (name, type_id, grade_id, stage_id, weight, price, specs_json)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![b.name, b.type_id, b.grade_id, b.stage_id, b.weight, b.price, specs_str],
+            )?;
+            let batch_id = tx.last_insert_rowid();
+
+            let tx_id = Self::begin_tx(&tx)?;
+            Self::record_batch_datoms(&tx, tx_id, batch_id, b)?;
+            Self::adjust_stage_aggregate(&tx, b.stage_id, b.weight, 1)?;
+
+            tx.commit()?;
+            batch_id
+        };
+        self.emit(ChangeEvent::BatchAdded { batch_id, stage_id: b.stage_id });
+        Ok(batch_id)
+    }
+
+    /// Deletes a batch, subtracting it from its stage's cached aggregate and
+    /// retracting every attribute it currently asserts in the datom log.
+    pub fn delete_inventory_batch(&self, batch_id: i64) -> Result<()> {
+        let stage_id = {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+
+            let (stage_id, weight): (i64, f64) = tx.query_row(
+                "SELECT stage_id, weight FROM inventory_batches WHERE id = ?1",
+                params![batch_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            tx.execute("DELETE FROM inventory_batches WHERE id = ?1", params![batch_id])?;
+            Self::adjust_stage_aggregate(&tx, stage_id, -weight, -1)?;
+
+            let attrs = Self::current_attrs(&tx, batch_id)?;
+            let tx_id = Self::begin_tx(&tx)?;
+            for attribute in attrs.keys() {
+                Self::record_datom(&tx, tx_id, batch_id, attribute, "null", false)?;
+            }
+
+            tx.commit()?;
+            stage_id
+        };
+        self.emit(ChangeEvent::BatchDeleted { batch_id, stage_id });
+        Ok(())
+    }
+
+    /// Checks `b.specs` against its product type's `SpecDef`s without
+    /// attempting to insert. `insert_inventory_batch` runs the same check
+    /// internally; use this to preview errors ahead of time.
+    pub fn validate_batch(&self, b: &InventoryBatch) -> Result<Vec<SpecError>> {
         let conn = self.conn.lock().unwrap();
-        let specs_str = serde_json::to_string(&b.specs)
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let defs = Self::fetch_spec_defs(&conn, b.type_id)?;
+        Ok(Self::check_specs(&defs, b))
+    }
 
+    /// Reads the materialized per-stage total in O(1) instead of rescanning
+    /// `inventory_batches`.
+    pub fn aggregate_stage_totals(&self, stage_id: i64) -> Result<(f64, usize)> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT total_weight, batch_count FROM stage_aggregates WHERE stage_id = ?1")?;
+        let result = stmt.query_row(params![stage_id], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as usize)));
+        match result {
+            Ok(totals) => Ok(totals),
+            Err(rusqlite::Error::QueryReturnedNoRows) => Ok((0.0, 0)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Recomputes `stage_aggregates` from scratch by rescanning
+    /// `inventory_batches`, with the same query `migration_v3` backfills
+    /// with, replacing whatever the incrementally maintained cache held.
+    pub fn rebuild_aggregates(&self) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute("DELETE FROM stage_aggregates", [])?;
         conn.execute(
-            "INSERT INTO inventory_batches (name, type_id, grade_id, stage_id, weight, price, specs_json)
-             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
-            params![b.name, b.type_id, b.grade_id, b.stage_id, b.weight, b.price, specs_str],
+            "INSERT INTO stage_aggregates (stage_id, total_weight, batch_count)
+             SELECT stage_id, COALESCE(SUM(weight), 0.0), COUNT(*) FROM inventory_batches GROUP BY stage_id",
+            [],
         )?;
-        Ok(conn.last_insert_rowid())
+        Ok(())
     }
 
-    pub fn aggregate_stage_totals(&self, stage_id: i64) -> Result<(f64, usize)> {
+    /// Same as `aggregate_stage_totals`, but computed against the
+    /// reconstructed "as of `timestamp`" state instead of the live table.
+    pub fn aggregate_stage_totals_as_of(&self, stage_id: i64, timestamp: &str) -> Result<(f64, usize)> {
+        let batches = self.inventory_as_of(timestamp)?;
+        let matching: Vec<&InventoryBatch> = batches
+            .iter()
+            .filter(|(_, b)| b.stage_id == stage_id)
+            .map(|(_, b)| b)
+            .collect();
+        let weight: f64 = matching.iter().map(|b| b.weight).sum();
+        Ok((weight, matching.len()))
+    }
+
+    /// Moves a batch to `to_stage_id`, recording the weight change as a
+    /// `stage_transitions` row (with `yield_ratio = weight_after /
+    /// weight_before`) and updating `inventory_batches` so the batch only
+    /// counts toward its new stage going forward. `stage_id` and `weight` are
+    /// also re-asserted with their new values in the datom log so
+    /// `batch_history` reflects the move; no retraction datom is written, the
+    /// new assertion supersedes the old value.
+    pub fn transition_batch(&self, batch_id: i64, to_stage_id: i64, new_weight: f64, note: Option<&str>) -> Result<()> {
+        Self::check_finite("weight", new_weight)?;
+        let from_stage_id = {
+            let mut conn = self.conn.lock().unwrap();
+            let tx = conn.transaction()?;
+
+            let (from_stage_id, weight_before): (i64, f64) = tx.query_row(
+                "SELECT stage_id, weight FROM inventory_batches WHERE id = ?1",
+                params![batch_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            // weight_before is a valid, finite batch weight, but 0.0 is legal
+            // (check_finite only rejects NaN/infinity) and would otherwise
+            // divide by zero here, storing an infinite yield_ratio that
+            // poisons yield_report's AVG/MIN/MAX for every row it's grouped
+            // with. There's no meaningful ratio to report in that case.
+            let yield_ratio = if weight_before > 0.0 { new_weight / weight_before } else { 0.0 };
+
+            tx.execute(
+                "INSERT INTO stage_transitions (batch_id, from_stage_id, to_stage_id, weight_before, weight_after, yield_ratio, note)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![batch_id, from_stage_id, to_stage_id, weight_before, new_weight, yield_ratio, note],
+            )?;
+
+            tx.execute(
+                "UPDATE inventory_batches SET stage_id = ?1, weight = ?2 WHERE id = ?3",
+                params![to_stage_id, new_weight, batch_id],
+            )?;
+
+            Self::adjust_stage_aggregate(&tx, from_stage_id, -weight_before, -1)?;
+            Self::adjust_stage_aggregate(&tx, to_stage_id, new_weight, 1)?;
+
+            let tx_id = Self::begin_tx(&tx)?;
+            Self::record_datom(&tx, tx_id, batch_id, "stage_id", &serde_json::to_string(&to_stage_id)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?, true)?;
+            Self::record_datom(&tx, tx_id, batch_id, "weight", &serde_json::to_string(&new_weight)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?, true)?;
+
+            tx.commit()?;
+            from_stage_id
+        };
+        self.emit(ChangeEvent::BatchMoved { batch_id, from_stage_id, to_stage_id });
+        Ok(())
+    }
+
+    /// For each stage-to-stage hop seen in `stage_transitions` for batches of
+    /// `type_id`, returns `(from_stage_id, to_stage_id, avg_yield, min_yield,
+    /// max_yield)` so callers can see typical shrinkage per hop.
+    pub fn yield_report(&self, type_id: i64) -> Result<Vec<(i64, i64, f64, f64, f64)>> {
         let conn = self.conn.lock().unwrap();
-        let mut stmt = conn.prepare("SELECT COALESCE(SUM(weight), 0.0), COUNT(*) FROM inventory_batches WHERE stage_id = ?1")?;
-        Ok(stmt.query_row(params![stage_id], |row| Ok((row.get(0)?, row.get(1)?)))?)
+        let mut stmt = conn.prepare(
+            "SELECT st.from_stage_id, st.to_stage_id,
+                    AVG(st.yield_ratio), MIN(st.yield_ratio), MAX(st.yield_ratio)
+             FROM stage_transitions st
+             JOIN inventory_batches b ON b.id = st.batch_id
+             WHERE b.type_id = ?1
+             GROUP BY st.from_stage_id, st.to_stage_id
+             ORDER BY st.from_stage_id ASC, st.to_stage_id ASC",
+        )?;
+        let rows = stmt.query_map(params![type_id], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+        })?;
+        let mut result = Vec::new();
+        for r in rows { result.push(r?); }
+        Ok(result)
+    }
+
+    /// Groups every inventory batch by `group_by` and computes `measures`
+    /// for each group. Rows are read in a single SQL scan and grouped in
+    /// Rust, with `SpecAvg` measures folded in the same pass since specs live
+    /// in `specs_json`, not a SQL column. Returns `(group_ids, values)` pairs —
+    /// one id per `group_by` entry, one value per `measures` entry, both in
+    /// the caller's order. `Model::report` resolves the ids to names.
+    pub fn report_groups(&self, group_by: &[Dimension], measures: &[Measure]) -> Result<Vec<(Vec<i64>, Vec<f64>)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT stage_id, grade_id, type_id, weight, price, specs_json FROM inventory_batches",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, f64>(3)?,
+                row.get::<_, f64>(4)?,
+                row.get::<_, String>(5)?,
+            ))
+        })?;
+
+        let mut groups: HashMap<Vec<i64>, GroupAccum> = HashMap::new();
+        for r in rows {
+            let (stage_id, grade_id, type_id, weight, price, specs_json) = r?;
+            let specs: HashMap<String, f64> = serde_json::from_str(&specs_json).unwrap_or_default();
+
+            let key: Vec<i64> = group_by
+                .iter()
+                .map(|d| match d {
+                    Dimension::Stage => stage_id,
+                    Dimension::Grade => grade_id,
+                    Dimension::Type => type_id,
+                })
+                .collect();
+
+            let accum = groups.entry(key).or_insert_with(GroupAccum::default);
+            accum.count += 1;
+            accum.weight += weight;
+            accum.value += weight * price;
+            for measure in measures {
+                if let Measure::SpecAvg(spec_key) = measure {
+                    if let Some(&value) = specs.get(spec_key) {
+                        let entry = accum.spec_sums.entry(spec_key.clone()).or_insert((0.0, 0.0));
+                        entry.0 += value * weight;
+                        entry.1 += weight;
+                    }
+                }
+            }
+        }
+
+        let mut result: Vec<(Vec<i64>, Vec<f64>)> = groups
+            .into_iter()
+            .map(|(key, accum)| {
+                let values = measures
+                    .iter()
+                    .map(|m| match m {
+                        Measure::Weight => accum.weight,
+                        Measure::Value => accum.value,
+                        Measure::Count => accum.count as f64,
+                        Measure::SpecAvg(spec_key) => accum
+                            .spec_sums
+                            .get(spec_key)
+                            .filter(|(_, w)| *w > 0.0)
+                            .map(|(sum, w)| sum / w)
+                            .unwrap_or(0.0),
+                    })
+                    .collect();
+                (key, values)
+            })
+            .collect();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(result)
     }
 
     // --- Config Methods ---
     
     // Stages
     pub fn insert_stage(&self, name: String) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute("INSERT OR IGNORE INTO stages (name) VALUES (?1)", params![name])?;
-        Ok(conn.last_insert_rowid())
+        let stage_id = {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("INSERT OR IGNORE INTO stages (name) VALUES (?1)", params![name])?;
+            conn.last_insert_rowid()
+        };
+        self.emit(ChangeEvent::StageAdded { stage_id });
+        Ok(stage_id)
     }
     pub fn get_all_stages(&self) -> Result<Vec<(i64, String)>> {
         let conn = self.conn.lock().unwrap();
@@ -130,9 +807,13 @@ impl Database {
 
     // Grades
     pub fn insert_grade(&self, name: String) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
-        conn.execute("INSERT OR IGNORE INTO grades (name) VALUES (?1)", params![name])?;
-        Ok(conn.last_insert_rowid())
+        let grade_id = {
+            let conn = self.conn.lock().unwrap();
+            conn.execute("INSERT OR IGNORE INTO grades (name) VALUES (?1)", params![name])?;
+            conn.last_insert_rowid()
+        };
+        self.emit(ChangeEvent::GradeAdded { grade_id });
+        Ok(grade_id)
     }
     pub fn get_all_grades(&self) -> Result<Vec<(i64, String)>> {
         let conn = self.conn.lock().unwrap();
@@ -144,15 +825,46 @@ impl Database {
     }
 
     // Product Types
+    /// Registers a product type and a default `SpecDef` for each of its spec
+    /// keys (no unit, no bounds, required). Use `set_spec_defs` afterwards to
+    /// attach real units/bounds.
     pub fn insert_product_type(&self, name: String, specs_keys: Vec<String>) -> Result<i64> {
-        let conn = self.conn.lock().unwrap();
-        let specs_json = serde_json::to_string(&specs_keys)
-            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
-        
-        conn.execute("INSERT OR IGNORE INTO product_types (name, specs_keys_json) VALUES (?1, ?2)", params![name, specs_json])?;
-        Ok(conn.last_insert_rowid())
+        let type_id = {
+            let mut conn = self.conn.lock().unwrap();
+            let specs_json = serde_json::to_string(&specs_keys)
+                .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+            let tx = conn.transaction()?;
+            let changed = tx.execute("INSERT OR IGNORE INTO product_types (name, specs_keys_json) VALUES (?1, ?2)", params![name, specs_json])?;
+            // `name` is UNIQUE, so a duplicate makes the INSERT OR IGNORE a
+            // no-op and last_insert_rowid() would keep returning whatever row
+            // this connection last inserted into *any* table. Look the
+            // existing id up by name instead of trusting it in that case.
+            let type_id = if changed > 0 {
+                tx.last_insert_rowid()
+            } else {
+                tx.query_row("SELECT id FROM product_types WHERE name = ?1", params![name], |row| row.get(0))?
+            };
+
+            // A duplicate name leaves the existing type (and its spec keys)
+            // untouched, so its spec_defs are left untouched too.
+            if changed > 0 {
+                for key in &specs_keys {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO spec_defs (type_id, key, unit, min_value, max_value, required)
+                         VALUES (?1, ?2, '', NULL, NULL, 1)",
+                        params![type_id, key],
+                    )?;
+                }
+            }
+
+            tx.commit()?;
+            type_id
+        };
+        self.emit(ChangeEvent::TypeAdded { type_id });
+        Ok(type_id)
     }
-    
+
     pub fn get_all_product_types(&self) -> Result<Vec<(i64, String, Vec<String>)>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare("SELECT id, name, specs_keys_json FROM product_types ORDER BY id ASC")?;
@@ -165,4 +877,128 @@ impl Database {
         for r in rows { result.push(r?); }
         Ok(result)
     }
-}
\ No newline at end of file
+
+    /// Reads the `SpecDef`s registered for a product type.
+    pub fn get_spec_defs(&self, type_id: i64) -> Result<Vec<SpecDef>> {
+        let conn = self.conn.lock().unwrap();
+        Self::fetch_spec_defs(&conn, type_id)
+    }
+
+    /// Replaces every `SpecDef` registered for `type_id` with `defs`.
+    pub fn set_spec_defs(&self, type_id: i64, defs: &[SpecDef]) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute("DELETE FROM spec_defs WHERE type_id = ?1", params![type_id])?;
+        for def in defs {
+            tx.execute(
+                "INSERT INTO spec_defs (type_id, key, unit, min_value, max_value, required)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![type_id, def.key, def.unit, def.min, def.max, def.required],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn test_db() -> Database {
+        let file = NamedTempFile::new().unwrap();
+        Database::open(file.path()).unwrap()
+    }
+
+    #[test]
+    fn open_rejects_a_schema_newer_than_this_binary_supports() {
+        let file = NamedTempFile::new().unwrap();
+        // First open runs migrations up to CURRENT_VERSION, then drop the
+        // connection so the file isn't locked for the reopen below.
+        drop(Database::open(file.path()).unwrap());
+
+        let conn = Connection::open(file.path()).unwrap();
+        conn.pragma_update(None, "user_version", CURRENT_VERSION + 1).unwrap();
+        drop(conn);
+
+        assert!(Database::open(file.path()).is_err());
+    }
+
+    #[test]
+    fn check_specs_flags_missing_out_of_bounds_and_unknown() {
+        let defs = vec![
+            SpecDef { key: "THC".into(), unit: "%".into(), min: Some(0.0), max: Some(30.0), required: true },
+            SpecDef { key: "Moisture".into(), unit: "%".into(), min: None, max: None, required: false },
+        ];
+        let mut specs = HashMap::new();
+        specs.insert("THC".to_string(), 45.0);
+        specs.insert("Extra".to_string(), 1.0);
+        let b = InventoryBatch { name: "B".into(), type_id: 1, grade_id: 1, stage_id: 1, weight: 1.0, price: 1.0, specs };
+
+        let errors = Database::check_specs(&defs, &b);
+        assert!(errors.contains(&SpecError::OutOfBounds { key: "THC".into(), value: 45.0, min: Some(0.0), max: Some(30.0) }));
+        assert!(errors.contains(&SpecError::Unknown("Extra".into())));
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn check_finite_rejects_nan_and_infinity() {
+        assert!(Database::check_finite("weight", f64::NAN).is_err());
+        assert!(Database::check_finite("weight", f64::INFINITY).is_err());
+        assert!(Database::check_finite("weight", 12.5).is_ok());
+    }
+
+    #[test]
+    fn batch_from_attrs_roundtrips_a_full_batch() {
+        let mut specs = HashMap::new();
+        specs.insert("THC".to_string(), 22.5);
+        let b = InventoryBatch { name: "Batch A".into(), type_id: 1, grade_id: 2, stage_id: 3, weight: 10.0, price: 5.0, specs };
+
+        let mut attrs = HashMap::new();
+        attrs.insert("name".to_string(), serde_json::to_string(&b.name).unwrap());
+        attrs.insert("type_id".to_string(), serde_json::to_string(&b.type_id).unwrap());
+        attrs.insert("grade_id".to_string(), serde_json::to_string(&b.grade_id).unwrap());
+        attrs.insert("stage_id".to_string(), serde_json::to_string(&b.stage_id).unwrap());
+        attrs.insert("weight".to_string(), serde_json::to_string(&b.weight).unwrap());
+        attrs.insert("price".to_string(), serde_json::to_string(&b.price).unwrap());
+        attrs.insert("specs".to_string(), serde_json::to_string(&b.specs).unwrap());
+
+        let rebuilt = Database::batch_from_attrs(&attrs).unwrap();
+        assert_eq!(rebuilt.name, b.name);
+        assert_eq!(rebuilt.specs, b.specs);
+    }
+
+    #[test]
+    fn batch_from_attrs_returns_none_when_an_attribute_is_missing() {
+        assert!(Database::batch_from_attrs(&HashMap::new()).is_none());
+    }
+
+    #[test]
+    fn report_groups_computes_weighted_spec_average() {
+        let db = test_db();
+        let type_id = db.insert_product_type("Flower".into(), vec!["THC".into()]).unwrap();
+        let stage_id = db.insert_stage("Dry".into()).unwrap();
+        let grade_id = db.insert_grade("A".into()).unwrap();
+
+        let mut specs_a = HashMap::new();
+        specs_a.insert("THC".to_string(), 20.0);
+        db.insert_inventory_batch(&InventoryBatch { name: "A".into(), type_id, grade_id, stage_id, weight: 1.0, price: 1.0, specs: specs_a }).unwrap();
+
+        let mut specs_b = HashMap::new();
+        specs_b.insert("THC".to_string(), 30.0);
+        db.insert_inventory_batch(&InventoryBatch { name: "B".into(), type_id, grade_id, stage_id, weight: 3.0, price: 1.0, specs: specs_b }).unwrap();
+
+        let groups = db.report_groups(&[Dimension::Stage], &[Measure::SpecAvg("THC".into())]).unwrap();
+        assert_eq!(groups.len(), 1);
+        // weighted average: (20*1 + 30*3) / (1+3) = 27.5
+        assert!((groups[0].1[0] - 27.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn insert_product_type_is_idempotent_on_duplicate_name() {
+        let db = test_db();
+        let first = db.insert_product_type("Flower".into(), vec!["THC".into()]).unwrap();
+        let second = db.insert_product_type("Flower".into(), vec!["Moisture".into()]).unwrap();
+        assert_eq!(first, second);
+    }
+}
@@ -1,7 +1,7 @@
 use slint::{ComponentHandle, SharedString, ModelRc, VecModel, Model as SlintModel}; 
 use crate::db::Database;
 // We alias your 'Model' struct to 'AppModel' to avoid conflict with Slint's 'Model' trait
-use crate::model::{InventoryBatch, Model as AppModel}; 
+use crate::model::{ChangeEvent, InventoryBatch, Model as AppModel, SpecError};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 
@@ -17,6 +17,25 @@ struct AppCache {
     types: Vec<(i64, Vec<String>)>, 
 }
 
+// Rebuilds the per-stage dashboard tiles from scratch. `stage_total_weight`/
+// `stage_batch_count` used to be singular properties that could only ever
+// display one stage at a time, so a transition's origin and destination
+// totals couldn't be shown together no matter which stage_id(s) triggered
+// the refresh. `stage_totals` is a list, indexed like `stages_list`, so
+// every tile reflects its own stage independently.
+fn refresh_stage_totals(ui: &MainWindow, db: &Database) {
+    if let Ok(stages) = db.get_all_stages() {
+        let totals: Vec<StageTotal> = stages
+            .iter()
+            .map(|(id, _)| {
+                let (weight, count) = db.aggregate_stage_totals(*id).unwrap_or((0.0, 0));
+                StageTotal { id: *id as i32, total_weight: weight as f32, batch_count: count as i32 }
+            })
+            .collect();
+        ui.set_stage_totals(ModelRc::new(VecModel::from(totals)));
+    }
+}
+
 fn refresh_lists(ui: &MainWindow, db: &Database, cache: &Arc<Mutex<AppCache>>) {
     let mut cache = cache.lock().unwrap();
 
@@ -75,32 +94,50 @@ fn main() -> Result<(), slint::PlatformError> {
     let cache = Arc::new(Mutex::new(AppCache { stages: vec![], grades: vec![], types: vec![] }));
 
     refresh_lists(&ui, &model.db, &cache);
+    refresh_stage_totals(&ui, &model.db);
 
-    // --- CONFIG CALLBACKS ---
-    ui.on_add_new_stage({
+    // A single observer replaces the manual refresh/update calls that used
+    // to follow every mutating callback below: it fires only after a write
+    // has actually committed, so it can't refresh against a stale read.
+    model.db.on_change({
         let ui_handle = ui.as_weak();
         let db = model.db.clone();
         let c = cache.clone();
+        move |event| {
+            let Some(ui) = ui_handle.upgrade() else { return };
+            match event {
+                ChangeEvent::StageAdded { .. } | ChangeEvent::GradeAdded { .. } | ChangeEvent::TypeAdded { .. } => {
+                    refresh_lists(&ui, &db, &c);
+                    refresh_stage_totals(&ui, &db);
+                }
+                ChangeEvent::BatchAdded { .. } | ChangeEvent::BatchDeleted { .. } | ChangeEvent::BatchMoved { .. } => {
+                    // Every batch write can change at most the two stages
+                    // involved, but stage_totals is small (one row per
+                    // stage) and rebuilding it in full keeps this in step
+                    // with refresh_lists' own full-rebuild style.
+                    refresh_stage_totals(&ui, &db);
+                }
+            }
+        }
+    });
+
+    // --- CONFIG CALLBACKS ---
+    ui.on_add_new_stage({
+        let db = model.db.clone();
         move |name| {
             if !name.is_empty() { let _ = db.insert_stage(name.to_string()); }
-            if let Some(ui) = ui_handle.upgrade() { refresh_lists(&ui, &db, &c); }
         }
     });
 
     ui.on_add_new_grade({
-        let ui_handle = ui.as_weak();
         let db = model.db.clone();
-        let c = cache.clone();
         move |name| {
             if !name.is_empty() { let _ = db.insert_grade(name.to_string()); }
-            if let Some(ui) = ui_handle.upgrade() { refresh_lists(&ui, &db, &c); }
         }
     });
 
     ui.on_add_new_type({
-        let ui_handle = ui.as_weak();
         let db = model.db.clone();
-        let c = cache.clone();
         move |name, specs_str| {
             if !name.is_empty() {
                 let keys: Vec<String> = specs_str.split(',')
@@ -109,7 +146,6 @@ fn main() -> Result<(), slint::PlatformError> {
                     .collect();
                 let _ = db.insert_product_type(name.to_string(), keys);
             }
-            if let Some(ui) = ui_handle.upgrade() { refresh_lists(&ui, &db, &c); }
         }
     });
 
@@ -119,36 +155,42 @@ fn main() -> Result<(), slint::PlatformError> {
         move |index| {
             let cache = c.lock().unwrap();
             if let Some((_, keys)) = cache.types.get(index as usize) {
-                let fields: Vec<SpecField> = keys.iter().map(|k| SpecField { 
-                    name: k.into(), 
-                    value: "".into() 
+                let fields: Vec<SpecField> = keys.iter().map(|k| SpecField {
+                    name: k.into(),
+                    value: "".into(),
+                    error: "".into(),
                 }).collect();
                 if let Some(ui) = ui_handle.upgrade() {
                     ui.set_active_specs(ModelRc::new(VecModel::from(fields)));
+                    ui.set_validation_errors(ModelRc::new(VecModel::from(Vec::<SharedString>::new())));
                 }
             }
         }
     });
 
     ui.on_add_batch({
-        let ui_handle = ui.as_weak();
         let db = model.db.clone();
+        let ui_handle = ui.as_weak();
         let c = cache.clone();
         move |type_idx, name, w_str, p_str, grade_idx, stage_idx, spec_values| {
             let cache = c.lock().unwrap();
-            
+
             let type_data = cache.types.get(type_idx as usize);
             let type_id = type_data.map(|(id, _)| *id).unwrap_or(0);
-            
+
             // Explicitly type the HashMap to fix inference error
             let mut specs_map: HashMap<String, f64> = HashMap::new();
-            
+
             if let Some((_, keys)) = type_data {
-                // Now .iter() works because we imported SlintModel
+                // Now .iter() works because we imported SlintModel. A spec
+                // field that doesn't parse is left out of the map entirely
+                // rather than silently coerced to 0.0 — the spec check below
+                // then reports it as missing if that spec is required.
                 for (i, field_struct) in spec_values.iter().enumerate() {
                     if let Some(key) = keys.get(i) {
-                         let v = field_struct.value.parse::<f64>().unwrap_or(0.0);
-                         specs_map.insert(key.clone(), v);
+                        if let Ok(v) = field_struct.value.parse::<f64>() {
+                            specs_map.insert(key.clone(), v);
+                        }
                     }
                 }
             }
@@ -172,14 +214,70 @@ fn main() -> Result<(), slint::PlatformError> {
                 specs: specs_map,
             };
 
-            if let Ok(_) = db.insert_inventory_batch(&new_batch) {
+            // Validate specs up front so a rejection can highlight the
+            // offending SpecField(s) instead of only logging to stderr.
+            let spec_errors = match db.validate_batch(&new_batch) {
+                Ok(errors) => errors,
+                Err(e) => {
+                    eprintln!("Error validating batch for type {}: {}", new_batch.type_id, e);
+                    Vec::new()
+                }
+            };
+
+            if !spec_errors.is_empty() {
+                let mut error_by_key: HashMap<String, String> = HashMap::new();
+                for err in &spec_errors {
+                    let key = match err {
+                        SpecError::Missing(key) => key,
+                        SpecError::OutOfBounds { key, .. } => key,
+                        SpecError::Unknown(key) => key,
+                    };
+                    let entry = error_by_key.entry(key.clone()).or_default();
+                    if !entry.is_empty() { entry.push_str("; "); }
+                    entry.push_str(&err.to_string());
+                }
+                let fields: Vec<SpecField> = spec_values.iter().map(|f| SpecField {
+                    name: f.name.clone(),
+                    value: f.value.clone(),
+                    error: error_by_key.get(f.name.as_str()).cloned().unwrap_or_default().into(),
+                }).collect();
+                let messages: Vec<SharedString> = spec_errors.iter().map(|e| SharedString::from(e.to_string())).collect();
                 if let Some(ui) = ui_handle.upgrade() {
-                    if let Ok((w, count)) = db.aggregate_stage_totals(new_batch.stage_id) {
-                         ui.set_stage_total_weight(w as f32);
-                         ui.set_stage_batch_count(count as i32);
-                    }
+                    ui.set_active_specs(ModelRc::new(VecModel::from(fields)));
+                    ui.set_validation_errors(ModelRc::new(VecModel::from(messages)));
                 }
+                return;
             }
+
+            // The observer registered above refreshes the stage tiles once
+            // this commits. insert_inventory_batch checks weight/price
+            // finiteness and re-checks specs inside the same transaction as
+            // the write, so a race against a concurrent spec_defs change is
+            // still caught even though we just validated above.
+            if let Err(e) = db.insert_inventory_batch(&new_batch) {
+                eprintln!("Batch '{}' rejected: {}", new_batch.name, e);
+                if let Some(ui) = ui_handle.upgrade() {
+                    ui.set_validation_errors(ModelRc::new(VecModel::from(vec![SharedString::from(e.to_string())])));
+                }
+                return;
+            }
+
+            if let Some(ui) = ui_handle.upgrade() {
+                ui.set_validation_errors(ModelRc::new(VecModel::from(Vec::<SharedString>::new())));
+            }
+        }
+    });
+
+    ui.on_move_batch({
+        let db = model.db.clone();
+        let c = cache.clone();
+        move |batch_id, stage_idx, w_str, note| {
+            let cache = c.lock().unwrap();
+            let to_stage_id = *cache.stages.get(stage_idx as usize).unwrap_or(&0);
+            let new_weight: f64 = w_str.parse().unwrap_or(0.0);
+            let note = if note.is_empty() { None } else { Some(note.as_str()) };
+
+            let _ = db.transition_batch(batch_id as i64, to_stage_id, new_weight, note);
         }
     });
 
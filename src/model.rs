@@ -35,10 +35,17 @@ impl Model {
         Model { db }
     }
 
-    /// Aggregates total weight and count for a given stage id. This is the
-    /// primary function used to populate the dashboard tiles.
-    pub fn aggregate_stage(&self, stage_id: i64) -> (f64, usize) {
-        match self.db.aggregate_stage_totals(stage_id) {
+    /// Aggregates total weight and count for a given stage id, the same
+    /// figures the dashboard tiles show. When `as_of` is
+    /// given (an ISO-8601 / `CURRENT_TIMESTAMP`-formatted string), the
+    /// aggregate is computed against the reconstructed historical state
+    /// instead of the live table.
+    pub fn aggregate_stage(&self, stage_id: i64, as_of: Option<&str>) -> (f64, usize) {
+        let result = match as_of {
+            Some(timestamp) => self.db.aggregate_stage_totals_as_of(stage_id, timestamp),
+            None => self.db.aggregate_stage_totals(stage_id),
+        };
+        match result {
             Ok((weight, count)) => (weight, count),
             Err(e) => {
                 eprintln!("Error aggregating stage {}: {}", stage_id, e);
@@ -46,6 +53,116 @@ impl Model {
             }
         }
     }
+
+    /// Reconstructs every inventory batch as it existed at `timestamp`.
+    pub fn inventory_as_of(&self, timestamp: &str) -> Vec<InventoryBatch> {
+        match self.db.inventory_as_of(timestamp) {
+            Ok(batches) => batches.into_iter().map(|(_, b)| b).collect(),
+            Err(e) => {
+                eprintln!("Error reconstructing inventory as of {}: {}", timestamp, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Returns the full change history of a batch as
+    /// `(tx_id, wall_time, attribute, old_value, new_value)` tuples.
+    pub fn batch_history(&self, batch_id: i64) -> Vec<(i64, String, String, Option<String>, Option<String>)> {
+        match self.db.batch_history(batch_id) {
+            Ok(history) => history,
+            Err(e) => {
+                eprintln!("Error fetching history for batch {}: {}", batch_id, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Moves a batch to `to_stage_id`, recording the resulting weight change
+    /// as a stage transition. Returns `false` (and logs) on failure so
+    /// callers can decide whether to refresh the UI.
+    pub fn transition(&self, batch_id: i64, to_stage_id: i64, new_weight: f64, note: Option<&str>) -> bool {
+        match self.db.transition_batch(batch_id, to_stage_id, new_weight, note) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Error transitioning batch {}: {}", batch_id, e);
+                false
+            }
+        }
+    }
+
+    /// Returns `(from_stage_id, to_stage_id, avg_yield, min_yield,
+    /// max_yield)` for every stage hop recorded for `type_id`'s batches.
+    pub fn yield_report(&self, type_id: i64) -> Vec<(i64, i64, f64, f64, f64)> {
+        match self.db.yield_report(type_id) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("Error building yield report for type {}: {}", type_id, e);
+                Vec::new()
+            }
+        }
+    }
+
+    /// Runs a grouped-aggregation report: one row per distinct combination
+    /// of `group_by` dimensions, with one computed value per `measures`
+    /// entry, resolved to human-readable names the Slint grid can render
+    /// directly.
+    pub fn report(&self, group_by: &[Dimension], measures: &[Measure]) -> ReportTable {
+        let groups = match self.db.report_groups(group_by, measures) {
+            Ok(groups) => groups,
+            Err(e) => {
+                eprintln!("Error building report: {}", e);
+                Vec::new()
+            }
+        };
+
+        let stage_names: HashMap<i64, String> = self.db.get_all_stages().unwrap_or_default().into_iter().collect();
+        let grade_names: HashMap<i64, String> = self.db.get_all_grades().unwrap_or_default().into_iter().collect();
+        let type_names: HashMap<i64, String> = self.db.get_all_product_types().unwrap_or_default()
+            .into_iter()
+            .map(|(id, name, _)| (id, name))
+            .collect();
+
+        let rows = groups
+            .into_iter()
+            .map(|(group_ids, values)| {
+                let group_names = group_ids
+                    .iter()
+                    .zip(group_by.iter())
+                    .map(|(id, dim)| {
+                        let names = match dim {
+                            Dimension::Stage => &stage_names,
+                            Dimension::Grade => &grade_names,
+                            Dimension::Type => &type_names,
+                        };
+                        names.get(id).cloned().unwrap_or_else(|| format!("#{}", id))
+                    })
+                    .collect();
+                ReportRow { group_ids, group_names, values }
+            })
+            .collect();
+
+        ReportTable {
+            dimensions: group_by.to_vec(),
+            measures: measures.to_vec(),
+            rows,
+        }
+    }
+
+    /// Checks `b.specs` against its product type's `SpecDef`s: every required
+    /// key must be present, every value must fall within its bounds, and no
+    /// unknown key may be supplied. `insert_inventory_batch` runs this same
+    /// check before writing; call this directly to preview errors (e.g. to
+    /// highlight offending fields) without attempting the insert.
+    pub fn validate_batch(&self, b: &InventoryBatch) -> Result<(), Vec<SpecError>> {
+        match self.db.validate_batch(b) {
+            Ok(errors) if errors.is_empty() => Ok(()),
+            Ok(errors) => Err(errors),
+            Err(e) => {
+                eprintln!("Error validating batch for type {}: {}", b.type_id, e);
+                Err(Vec::new())
+            }
+        }
+    }
 }
 
 // Optional: typed ProductTemplate for future UI bindings
@@ -56,6 +173,96 @@ pub struct ProductTemplate {
     pub required_specs: Vec<String>, // List of keys (e.g., ["THC", "Moisture"])
 }
 
+/// A notification `Database` emits after a mutating method's transaction
+/// commits. Register a handler with `Database::on_change` to react
+/// incrementally (UI refresh, export, logging) instead of polling or
+/// hand-updating state after every callback.
+#[derive(Debug, Clone)]
+pub enum ChangeEvent {
+    BatchAdded { batch_id: i64, stage_id: i64 },
+    BatchMoved { batch_id: i64, from_stage_id: i64, to_stage_id: i64 },
+    BatchDeleted { batch_id: i64, stage_id: i64 },
+    StageAdded { stage_id: i64 },
+    GradeAdded { grade_id: i64 },
+    TypeAdded { type_id: i64 },
+}
+
+/// A column to group a `Model::report` by. Each variant corresponds to one
+/// of the lookup tables a batch references.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dimension {
+    Stage,
+    Grade,
+    Type,
+}
+
+/// A column to compute within each group of a `Model::report`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Measure {
+    Weight,
+    Value,
+    Count,
+    /// Weight-weighted average of the named spec, over batches that have it.
+    SpecAvg(String),
+}
+
+/// One row of a `Model::report` pivot: the requested `Dimension`s' ids and
+/// resolved names, and one computed value per requested `Measure`, both in
+/// the same order the caller passed them.
+#[derive(Debug, Clone)]
+pub struct ReportRow {
+    pub group_ids: Vec<i64>,
+    pub group_names: Vec<String>,
+    pub values: Vec<f64>,
+}
+
+/// The result of `Model::report`: the `Dimension`s/`Measure`s that were
+/// requested, so a caller can label columns, plus one row per group.
+#[derive(Debug, Clone)]
+pub struct ReportTable {
+    pub dimensions: Vec<Dimension>,
+    pub measures: Vec<Measure>,
+    pub rows: Vec<ReportRow>,
+}
+
+/// The type descriptor for one spec key of a product type: its unit, the
+/// bounds a value must fall within, and whether a batch must supply it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpecDef {
+    pub key: String,
+    pub unit: String,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub required: bool,
+}
+
+/// A single reason a batch's `specs` failed validation against its product
+/// type's `SpecDef`s.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpecError {
+    Missing(String),
+    OutOfBounds { key: String, value: f64, min: Option<f64>, max: Option<f64> },
+    Unknown(String),
+}
+
+impl std::fmt::Display for SpecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SpecError::Missing(key) => write!(f, "missing required spec '{}'", key),
+            SpecError::OutOfBounds { key, value, min, max } => {
+                write!(f, "spec '{}' = {} is outside allowed range", key, value)?;
+                match (min, max) {
+                    (Some(min), Some(max)) => write!(f, " [{}, {}]", min, max),
+                    (Some(min), None) => write!(f, " [>= {}]", min),
+                    (None, Some(max)) => write!(f, " [<= {}]", max),
+                    (None, None) => Ok(()),
+                }
+            }
+            SpecError::Unknown(key) => write!(f, "unknown spec '{}' for this product type", key),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -68,4 +275,22 @@ mod tests {
         let b = InventoryBatch { name: "B".into(), type_id: 1, grade_id: 1, stage_id: 1, weight: 2.0, price: 3.0, specs: HashMap::new() };
         assert_eq!(b.total_value(), 6.0);
     }
+
+    #[test]
+    fn aggregate_stage_reflects_inserted_batches() {
+        let file = NamedTempFile::new().unwrap();
+        let db = Database::open(file.path()).unwrap();
+        let model = Model::new(db);
+
+        let type_id = model.db.insert_product_type("Flower".into(), vec![]).unwrap();
+        let stage_id = model.db.insert_stage("Dry".into()).unwrap();
+        let grade_id = model.db.insert_grade("A".into()).unwrap();
+
+        let b = InventoryBatch { name: "Batch".into(), type_id, grade_id, stage_id, weight: 4.0, price: 2.0, specs: HashMap::new() };
+        model.db.insert_inventory_batch(&b).unwrap();
+
+        let (weight, count) = model.aggregate_stage(stage_id, None);
+        assert_eq!(count, 1);
+        assert_eq!(weight, 4.0);
+    }
 }